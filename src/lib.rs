@@ -1,7 +1,11 @@
 mod protocol;
 mod server;
 pub use server::{
-    connect, BigServerDescription, ConnectionConfig, ExtraDescriptionPart, ForgeChannel, ForgeData,
-    ForgeModInfo, ForgeMods, ModInfo, ServerDescription, ServerError, ServerPlayer, ServerPlayers,
-    ServerVersion, StatusConnection, StatusResponse,
+    connect, BigServerDescription, ConnectionConfig, ExtraDescriptionPart, FaviconError,
+    ForgeChannel, ForgeData, ForgeModInfo, ForgeMods, LegacyStatusResponse, ModInfo,
+    NormalizedModInfo, ProtocolVersionCache, ServerDescription, ServerError, ServerPlayer,
+    ServerPlayers, ServerVersion, StatusConnection, StatusResponse,
 };
+
+#[cfg(feature = "image")]
+pub use server::FaviconImageError;
@@ -0,0 +1,463 @@
+//! Low level implementation of the packet framing used by the
+//! [ServerListPing](https://wiki.vg/Server_List_Ping) protocol: VarInt
+//! encoding and the handful of packets needed to perform a status query.
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Error, Debug)]
+pub enum ProtocolError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("varint is too big")]
+    VarIntTooBig,
+
+    #[error("received invalid utf8 data")]
+    InvalidString,
+
+    #[error("packet body was shorter than expected")]
+    UnexpectedEof,
+
+    #[error("malformed legacy ping response")]
+    MalformedLegacyResponse,
+}
+
+type Result<T> = std::result::Result<T, ProtocolError>;
+
+const STATUS_NEXT_STATE: i32 = 1;
+
+async fn read_varint<R: AsyncRead + Unpin + Send>(reader: &mut R) -> Result<i32> {
+    let mut value = 0i32;
+    let mut position = 0;
+
+    loop {
+        let byte = reader.read_u8().await?;
+        value |= ((byte & 0x7F) as i32) << position;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+
+        position += 7;
+        if position >= 32 {
+            return Err(ProtocolError::VarIntTooBig);
+        }
+    }
+
+    Ok(value)
+}
+
+fn encode_varint(mut value: i32, buf: &mut Vec<u8>) {
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value = ((value as u32) >> 7) as i32;
+
+        if value != 0 {
+            byte |= 0x80;
+        }
+
+        buf.push(byte);
+
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_string(value: &str, buf: &mut Vec<u8>) {
+    encode_varint(value.len() as i32, buf);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+/// Reads a VarInt from the front of `buf`, returning the value and the
+/// number of bytes it occupied.
+fn decode_varint(buf: &[u8]) -> Result<(i32, usize)> {
+    let mut value = 0i32;
+    let mut position = 0;
+
+    for (i, &byte) in buf.iter().enumerate() {
+        value |= ((byte & 0x7F) as i32) << position;
+
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+
+        position += 7;
+        if position >= 32 {
+            return Err(ProtocolError::VarIntTooBig);
+        }
+    }
+
+    Err(ProtocolError::Io(std::io::Error::from(
+        std::io::ErrorKind::UnexpectedEof,
+    )))
+}
+
+fn decode_string(buf: &[u8]) -> Result<(String, usize)> {
+    let (length, offset) = decode_varint(buf)?;
+    let end = offset
+        .checked_add(length as usize)
+        .ok_or(ProtocolError::UnexpectedEof)?;
+    let bytes = buf.get(offset..end).ok_or(ProtocolError::UnexpectedEof)?;
+    let string = String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidString)?;
+
+    Ok((string, end))
+}
+
+/// Implemented by every packet that can be sent or received over a
+/// [`StatusConnection`](crate::server::StatusConnection).
+pub trait RawPacket: Sized {
+    /// The packet id this packet is framed with on the wire.
+    const PACKET_ID: i32 = 0x00;
+
+    /// Encodes this packet's body (everything after the packet id) into
+    /// `buf`.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a packet's body from `buf`.
+    fn decode(buf: &[u8]) -> Result<Self>;
+}
+
+#[async_trait]
+pub trait AsyncWriteRawPacket {
+    async fn write_packet<P: RawPacket + Send + Sync>(&mut self, packet: P) -> Result<()>;
+}
+
+#[async_trait]
+pub trait AsyncReadRawPacket {
+    async fn read_packet<P: RawPacket>(&mut self) -> Result<P>;
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send> AsyncWriteRawPacket for W {
+    async fn write_packet<P: RawPacket + Send + Sync>(&mut self, packet: P) -> Result<()> {
+        let mut body = Vec::new();
+        encode_varint(P::PACKET_ID, &mut body);
+        packet.encode(&mut body);
+
+        let mut out = Vec::new();
+        encode_varint(body.len() as i32, &mut out);
+        out.extend_from_slice(&body);
+
+        self.write_all(&out).await?;
+        self.flush().await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R: AsyncRead + Unpin + Send> AsyncReadRawPacket for R {
+    async fn read_packet<P: RawPacket>(&mut self) -> Result<P> {
+        let length = read_varint(self).await?;
+
+        let mut buf = vec![0u8; length as usize];
+        self.read_exact(&mut buf).await?;
+
+        let mut slice = &buf[..];
+        let _packet_id = read_varint(&mut slice).await?;
+
+        P::decode(slice)
+    }
+}
+
+/// The first packet sent to the server, announcing the protocol version
+/// and requesting the `status` next state.
+pub struct HandshakePacket {
+    protocol_version: i32,
+    server_address: String,
+    server_port: u16,
+}
+
+impl HandshakePacket {
+    pub fn new(protocol_version: i32, server_address: String, server_port: u16) -> Self {
+        HandshakePacket {
+            protocol_version,
+            server_address,
+            server_port,
+        }
+    }
+}
+
+impl RawPacket for HandshakePacket {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_varint(self.protocol_version, buf);
+        encode_string(&self.server_address, buf);
+        buf.extend_from_slice(&self.server_port.to_be_bytes());
+        encode_varint(STATUS_NEXT_STATE, buf);
+    }
+
+    fn decode(_buf: &[u8]) -> Result<Self> {
+        unimplemented!("HandshakePacket is only ever sent, never received")
+    }
+}
+
+/// Requests the server's status response. Carries no data.
+pub struct RequestPacket;
+
+impl RequestPacket {
+    pub fn new() -> Self {
+        RequestPacket
+    }
+}
+
+impl Default for RequestPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawPacket for RequestPacket {
+    fn encode(&self, _buf: &mut Vec<u8>) {}
+
+    fn decode(_buf: &[u8]) -> Result<Self> {
+        unimplemented!("RequestPacket is only ever sent, never received")
+    }
+}
+
+/// The server's reply to a [`RequestPacket`], containing the raw status
+/// JSON.
+pub struct ResponsePacket {
+    pub body: String,
+}
+
+impl RawPacket for ResponsePacket {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_string(&self.body, buf);
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let (body, _) = decode_string(buf)?;
+        Ok(ResponsePacket { body })
+    }
+}
+
+/// Sent after the status response to measure round-trip latency. The
+/// server is expected to echo the payload back in a [`PongPacket`].
+pub struct PingPacket {
+    pub payload: i64,
+}
+
+impl PingPacket {
+    pub fn new(payload: i64) -> Self {
+        PingPacket { payload }
+    }
+}
+
+impl RawPacket for PingPacket {
+    const PACKET_ID: i32 = 0x01;
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.payload.to_be_bytes());
+    }
+
+    fn decode(_buf: &[u8]) -> Result<Self> {
+        unimplemented!("PingPacket is only ever sent, never received")
+    }
+}
+
+/// The server's echo of a [`PingPacket`].
+pub struct PongPacket {
+    pub payload: i64,
+}
+
+impl RawPacket for PongPacket {
+    const PACKET_ID: i32 = 0x01;
+
+    fn encode(&self, _buf: &mut Vec<u8>) {
+        unimplemented!("PongPacket is only ever received, never sent")
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let bytes: [u8; 8] = buf
+            .get(..8)
+            .and_then(|slice| slice.try_into().ok())
+            .ok_or(ProtocolError::UnexpectedEof)?;
+
+        Ok(PongPacket {
+            payload: i64::from_be_bytes(bytes),
+        })
+    }
+}
+
+/// Arbitrary protocol version sent in the `MC|PingHost` plugin message.
+/// Legacy servers mostly ignore this value; it only has to be a valid
+/// byte.
+const LEGACY_PROTOCOL_VERSION: u8 = 74;
+
+fn encode_utf16be_string(value: &str, buf: &mut Vec<u8>) {
+    let units: Vec<u16> = value.encode_utf16().collect();
+    buf.extend_from_slice(&(units.len() as u16).to_be_bytes());
+
+    for unit in units {
+        buf.extend_from_slice(&unit.to_be_bytes());
+    }
+}
+
+/// The raw, still-stringly-typed fields extracted from a legacy
+/// (pre-1.7) Server List Ping response.
+pub struct LegacyPingResponse {
+    pub protocol_version: String,
+    pub version: String,
+    pub motd: String,
+    pub players_online: String,
+    pub max_players: String,
+}
+
+/// Sends a `MC|PingHost` plugin message, the 1.6-style legacy Server
+/// List Ping request.
+pub async fn write_legacy_ping<W: AsyncWrite + Unpin + Send>(
+    writer: &mut W,
+    address: &str,
+    port: u16,
+) -> Result<()> {
+    let mut data = Vec::new();
+    data.push(LEGACY_PROTOCOL_VERSION);
+    encode_utf16be_string(address, &mut data);
+    data.extend_from_slice(&(port as i32).to_be_bytes());
+
+    let mut packet = vec![0xFE, 0x01, 0xFA];
+    encode_utf16be_string("MC|PingHost", &mut packet);
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&data);
+
+    writer.write_all(&packet).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Reads and parses the `0xFF` kick packet a legacy server replies to a
+/// [`write_legacy_ping`] with.
+pub async fn read_legacy_ping_response<R: AsyncRead + Unpin + Send>(
+    reader: &mut R,
+) -> Result<LegacyPingResponse> {
+    let ident = reader.read_u8().await?;
+    if ident != 0xFF {
+        return Err(ProtocolError::MalformedLegacyResponse);
+    }
+
+    let char_count = reader.read_u16().await?;
+    let mut units = vec![0u16; char_count as usize];
+    for unit in units.iter_mut() {
+        *unit = reader.read_u16().await?;
+    }
+
+    let message =
+        String::from_utf16(&units).map_err(|_| ProtocolError::MalformedLegacyResponse)?;
+    let fields = message
+        .strip_prefix("\u{00a7}1\0")
+        .ok_or(ProtocolError::MalformedLegacyResponse)?;
+
+    let mut fields = fields.split('\0');
+    let mut next_field = || fields.next().ok_or(ProtocolError::MalformedLegacyResponse);
+
+    Ok(LegacyPingResponse {
+        protocol_version: next_field()?.to_string(),
+        version: next_field()?.to_string(),
+        motd: next_field()?.to_string(),
+        players_online: next_field()?.to_string(),
+        max_players: next_field()?.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn encode_legacy_response(fields: &str) -> Vec<u8> {
+        let units: Vec<u16> = fields.encode_utf16().collect();
+
+        let mut response = vec![0xFF];
+        response.extend_from_slice(&(units.len() as u16).to_be_bytes());
+        for unit in units {
+            response.extend_from_slice(&unit.to_be_bytes());
+        }
+
+        response
+    }
+
+    #[tokio::test]
+    async fn write_legacy_ping_encodes_the_ping_host_packet() {
+        let mut packet = Vec::new();
+        write_legacy_ping(&mut packet, "play.example.com", 25565)
+            .await
+            .unwrap();
+
+        assert_eq!(&packet[..3], &[0xFE, 0x01, 0xFA]);
+
+        // "MC|PingHost", UTF-16BE length-prefixed
+        assert_eq!(&packet[3..5], &11u16.to_be_bytes());
+        assert_eq!(
+            String::from_utf16(
+                &packet[5..5 + 11 * 2]
+                    .chunks_exact(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .collect::<Vec<_>>()
+            )
+            .unwrap(),
+            "MC|PingHost"
+        );
+
+        let rest = &packet[5 + 11 * 2..];
+        let remaining_len = u16::from_be_bytes([rest[0], rest[1]]);
+        let data = &rest[2..];
+        assert_eq!(remaining_len as usize, data.len());
+
+        assert_eq!(data[0], LEGACY_PROTOCOL_VERSION);
+
+        let host_len = u16::from_be_bytes([data[1], data[2]]);
+        let host_bytes = &data[3..3 + host_len as usize * 2];
+        let host: Vec<u16> = host_bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&host).unwrap(), "play.example.com");
+
+        let port_bytes = &data[3 + host_len as usize * 2..];
+        assert_eq!(i32::from_be_bytes(port_bytes.try_into().unwrap()), 25565);
+    }
+
+    #[tokio::test]
+    async fn read_legacy_ping_response_parses_the_kick_packet() {
+        let response =
+            encode_legacy_response("\u{a7}1\u{0}127\u{0}1.6.4\u{0}A Minecraft Server\u{0}5\u{0}20");
+        let mut cursor = Cursor::new(response);
+
+        let parsed = read_legacy_ping_response(&mut cursor).await.unwrap();
+
+        assert_eq!(parsed.protocol_version, "127");
+        assert_eq!(parsed.version, "1.6.4");
+        assert_eq!(parsed.motd, "A Minecraft Server");
+        assert_eq!(parsed.players_online, "5");
+        assert_eq!(parsed.max_players, "20");
+    }
+
+    #[tokio::test]
+    async fn read_legacy_ping_response_rejects_wrong_packet_id() {
+        let mut cursor = Cursor::new(vec![0x00, 0x00, 0x00]);
+
+        assert!(matches!(
+            read_legacy_ping_response(&mut cursor).await,
+            Err(ProtocolError::MalformedLegacyResponse)
+        ));
+    }
+
+    #[test]
+    fn pong_packet_decodes_the_echoed_payload() {
+        let pong = PongPacket::decode(&42i64.to_be_bytes()).unwrap();
+        assert_eq!(pong.payload, 42);
+    }
+
+    #[test]
+    fn pong_packet_rejects_a_too_short_buffer() {
+        assert!(matches!(
+            PongPacket::decode(&[0, 1, 2, 3]),
+            Err(ProtocolError::UnexpectedEof)
+        ));
+    }
+}
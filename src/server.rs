@@ -1,7 +1,12 @@
 //! This module defines a wrapper around Minecraft's
 //! [ServerListPing](https://wiki.vg/Server_List_Ping)
 
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, Result};
+use base64::Engine;
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
 use serde::Deserialize;
 use thiserror::Error;
 use tokio::net::TcpStream;
@@ -18,6 +23,30 @@ pub enum ServerError {
 
     #[error("invalid JSON response: \"{0}\"")]
     InvalidJson(serde_json::Error),
+
+    #[error("server echoed back a different payload than was sent")]
+    LatencyMismatch,
+}
+
+/// The decoded response to a legacy (pre-1.7) Server List Ping, as used
+/// by 1.4-1.6 and beta servers that don't understand the modern
+/// handshake+JSON protocol.
+#[derive(Debug)]
+pub struct LegacyStatusResponse {
+    /// The server's reported ServerListPing protocol version.
+    pub protocol_version: u32,
+
+    /// The server's Minecraft version, i.e. "1.6.4".
+    pub version: String,
+
+    /// The server's MOTD.
+    pub motd: String,
+
+    /// The number of players currently online.
+    pub players_online: u32,
+
+    /// The configured maximum number of players for the server.
+    pub max_players: u32,
 }
 
 impl From<protocol::ProtocolError> for ServerError {
@@ -102,6 +131,42 @@ pub struct ForgeModInfo {
     pub version: String,
 }
 
+/// this is the `forgeData` response sent by Forge 1.13.2+ servers,
+/// replacing the old `modinfo`/`FML` format
+#[derive(Debug, Deserialize)]
+pub struct ForgeData {
+    #[serde(rename = "fmlNetworkVersion")]
+    pub fml_network_version: u32,
+
+    pub channels: Vec<ForgeChannel>,
+
+    pub mods: Vec<ForgeMods>,
+}
+
+/// a network channel registered by a mod, as sent in `forgeData`
+#[derive(Debug, Deserialize)]
+pub struct ForgeChannel {
+    pub res: String,
+    pub version: String,
+    pub required: bool,
+}
+
+/// a mod entry as sent in `forgeData`'s `mods` list
+#[derive(Debug, Deserialize)]
+pub struct ForgeMods {
+    #[serde(rename = "modId")]
+    pub mod_id: String,
+    pub modmarker: String,
+}
+
+/// a single mod entry, normalized from either the old FML (`modinfo`)
+/// or new FML2 (`forgeData`) mod list formats
+#[derive(Debug)]
+pub struct NormalizedModInfo {
+    pub id: String,
+    pub version: String,
+}
+
 /// there are 2 variants of server descriptions
 /// the Simple variation is rarely used, but the minecraft client understands it
 /// so we should be compatible too
@@ -143,17 +208,116 @@ pub struct StatusResponse {
     pub favicon: Option<String>,
 
     pub modinfo: Option<ModInfo>,
+
+    /// The FML2 mod list, reported by Forge 1.13.2+ servers instead of
+    /// `modinfo`.
+    #[serde(rename = "forgeData")]
+    pub forge_data: Option<ForgeData>,
+}
+
+impl StatusResponse {
+    /// Returns a normalized mod list, regardless of whether the server
+    /// reported mods through the old FML `modinfo` field or the FML2
+    /// `forgeData` field.
+    pub fn mod_list(&self) -> Vec<NormalizedModInfo> {
+        if let Some(ModInfo::Forge { mod_list }) = &self.modinfo {
+            return mod_list
+                .iter()
+                .map(|m| NormalizedModInfo {
+                    id: m.modid.clone(),
+                    version: m.version.clone(),
+                })
+                .collect();
+        }
+
+        if let Some(forge_data) = &self.forge_data {
+            return forge_data
+                .mods
+                .iter()
+                .map(|m| NormalizedModInfo {
+                    id: m.mod_id.clone(),
+                    version: m.modmarker.clone(),
+                })
+                .collect();
+        }
+
+        Vec::new()
+    }
+
+    /// Decodes [`favicon`](Self::favicon) into raw PNG bytes, stripping
+    /// the `data:image/png;base64,` prefix and base64-decoding the
+    /// rest. Returns `None` if the server didn't send a favicon.
+    pub fn favicon_png(&self) -> Option<std::result::Result<Vec<u8>, FaviconError>> {
+        const PREFIX: &str = "data:image/png;base64,";
+
+        self.favicon.as_ref().map(|favicon| {
+            let encoded = favicon.strip_prefix(PREFIX).ok_or(FaviconError::MissingPrefix)?;
+            Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?)
+        })
+    }
+
+    /// Like [`favicon_png`](Self::favicon_png), but additionally decodes
+    /// the PNG into a [`DynamicImage`](image::DynamicImage). Minecraft
+    /// favicons are 64x64.
+    #[cfg(feature = "image")]
+    pub fn favicon_image(&self) -> Option<std::result::Result<image::DynamicImage, FaviconImageError>> {
+        self.favicon_png().map(|png| {
+            let png = png?;
+            Ok(image::load_from_memory(&png)?)
+        })
+    }
+}
+
+/// Error returned by [`StatusResponse::favicon_png`].
+#[derive(Error, Debug)]
+pub enum FaviconError {
+    #[error("favicon is missing the `data:image/png;base64,` prefix")]
+    MissingPrefix,
+
+    #[error("failed to decode base64 favicon data: {0}")]
+    InvalidBase64(#[from] base64::DecodeError),
 }
 
-const LATEST_PROTOCOL_VERSION: usize = 578;
+/// Error returned by [`StatusResponse::favicon_image`].
+#[cfg(feature = "image")]
+#[derive(Error, Debug)]
+pub enum FaviconImageError {
+    #[error(transparent)]
+    Favicon(#[from] FaviconError),
+
+    #[error("failed to decode favicon PNG: {0}")]
+    Image(#[from] image::ImageError),
+}
+
+const LATEST_PROTOCOL_VERSION: i32 = 578;
 const DEFAULT_PORT: u16 = 25565;
 
+/// Protocol version sent during [`connect_negotiated`](ConnectionConfig::connect_negotiated),
+/// the conventional value meaning "status query, protocol unknown".
+const NEGOTIATE_PROTOCOL_VERSION: i32 = -1;
+
+/// Caller-supplied cache mapping `address:port` to a previously
+/// negotiated protocol version, so repeated calls to
+/// [`ConnectionConfig::connect_negotiated_with_cache`] can skip the
+/// negotiation round-trip. Implementations are free to back this with
+/// an on-disk file, a database, or anything else.
+pub trait ProtocolVersionCache {
+    /// Looks up a previously cached protocol version for `key`
+    /// (`address:port`).
+    fn get(&self, key: &str) -> Option<i32>;
+
+    /// Stores a negotiated protocol version for `key` (`address:port`).
+    fn set(&self, key: &str, protocol_version: i32);
+}
+
 /// Builder for a Minecraft
 /// ServerListPing connection.
 pub struct ConnectionConfig {
-    protocol_version: usize,
+    protocol_version: i32,
     address: String,
     port: u16,
+    port_explicit: bool,
+    srv_lookup: bool,
 }
 
 impl ConnectionConfig {
@@ -164,6 +328,8 @@ impl ConnectionConfig {
             protocol_version: LATEST_PROTOCOL_VERSION,
             address,
             port: DEFAULT_PORT,
+            port_explicit: false,
+            srv_lookup: true,
         }
     }
 
@@ -172,21 +338,75 @@ impl ConnectionConfig {
     /// use. If not specified, the latest version
     /// will be used.
     pub fn with_protocol_version(mut self, protocol_version: usize) -> Self {
-        self.protocol_version = protocol_version;
+        self.protocol_version = protocol_version as i32;
         self
     }
 
     /// Sets a specific port for the
     /// connection to use. If not specified, the
     /// default port of 25565 will be used.
+    ///
+    /// Calling this disables the automatic SRV lookup, since an
+    /// explicit port means the caller already knows where to connect.
     pub fn with_port(mut self, port: u16) -> Self {
         self.port = port;
+        self.port_explicit = true;
         self
     }
 
+    /// Controls whether `connect` resolves a `_minecraft._tcp.<address>`
+    /// SRV record before connecting. Enabled by default, since many
+    /// servers publish one to point a vanity hostname at a different
+    /// host and port. Has no effect if `with_port` was called.
+    pub fn with_srv_lookup(mut self, srv_lookup: bool) -> Self {
+        self.srv_lookup = srv_lookup;
+        self
+    }
+
+    /// Resolves the `_minecraft._tcp.<address>` SRV record for this
+    /// connection's address, if one exists. Per RFC 2782, the record
+    /// with the lowest priority is preferred, with the highest weight
+    /// breaking ties.
+    async fn lookup_srv(address: &str) -> Option<(String, u16)> {
+        let resolver =
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
+
+        let lookup = resolver
+            .srv_lookup(format!("_minecraft._tcp.{address}"))
+            .await
+            .ok()?;
+        let srv = lookup
+            .iter()
+            .min_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())))?;
+
+        Some((srv.target().to_string().trim_end_matches('.').to_string(), srv.port()))
+    }
+
+    /// Determines the host and port to open the TCP connection to,
+    /// taking the SRV lookup into account.
+    async fn resolve_target(&self) -> (String, u16) {
+        if self.srv_lookup && !self.port_explicit {
+            if let Some(target) = Self::lookup_srv(&self.address).await {
+                return target;
+            }
+        }
+
+        (self.address.clone(), self.port)
+    }
+
     /// Connects to the server and consumes the builder.
     pub async fn connect(self) -> Result<StatusConnection> {
-        let stream = TcpStream::connect(format!("{}:{}", self.address, self.port))
+        let (host, port) = self.resolve_target().await;
+        self.connect_to(&host, port).await
+    }
+
+    /// Opens the TCP connection to an already-resolved `host`/`port`,
+    /// skipping [`resolve_target`](Self::resolve_target). Used to share
+    /// a single SRV lookup across multiple connections to the same
+    /// logical address, e.g. in
+    /// [`connect_negotiated_with_cache`](Self::connect_negotiated_with_cache).
+    async fn connect_to(self, host: &str, port: u16) -> Result<StatusConnection> {
+        let stream = TcpStream::connect((host, port))
             .await
             .map_err(|_| ServerError::FailedToConnect)?;
 
@@ -197,6 +417,57 @@ impl ConnectionConfig {
             port: self.port,
         })
     }
+
+    /// Like [`connect`](Self::connect), but negotiates the protocol
+    /// version with the server first: an initial handshake is sent on
+    /// its own connection with protocol version `-1` (the convention
+    /// for "status query, protocol unknown"), that connection is
+    /// discarded, and a fresh one is opened pinned to the
+    /// `version.protocol` the server reported. A single Status-state
+    /// connection only supports one Handshake, so the negotiated
+    /// version can't just be patched onto the probe connection.
+    pub async fn connect_negotiated(self) -> Result<StatusConnection> {
+        self.connect_negotiated_with_cache(None).await
+    }
+
+    /// Like [`connect_negotiated`](Self::connect_negotiated), but
+    /// consults `cache` (keyed by `address:port`) for a previously
+    /// negotiated protocol version before performing the negotiation
+    /// round-trip, and populates it afterwards.
+    pub async fn connect_negotiated_with_cache(
+        mut self,
+        cache: Option<&dyn ProtocolVersionCache>,
+    ) -> Result<StatusConnection> {
+        let cache_key = format!("{}:{}", self.address, self.port);
+        let (host, port) = self.resolve_target().await;
+
+        if let Some(protocol_version) = cache.and_then(|cache| cache.get(&cache_key)) {
+            self.protocol_version = protocol_version;
+            return self.connect_to(&host, port).await;
+        }
+
+        let probe = ConnectionConfig {
+            protocol_version: NEGOTIATE_PROTOCOL_VERSION,
+            address: self.address.clone(),
+            port: self.port,
+            port_explicit: self.port_explicit,
+            srv_lookup: self.srv_lookup,
+        };
+        let negotiated = probe
+            .connect_to(&host, port)
+            .await?
+            .status()
+            .await?
+            .version
+            .protocol as i32;
+
+        if let Some(cache) = cache {
+            cache.set(&cache_key, negotiated);
+        }
+
+        self.protocol_version = negotiated;
+        self.connect_to(&host, port).await
+    }
 }
 
 /// Convenience wrapper for easily connecting
@@ -206,10 +477,21 @@ pub async fn connect(address: String) -> Result<StatusConnection> {
     ConnectionConfig::build(address).connect().await
 }
 
+/// Checks that a [`PongPacket`](protocol::PongPacket) echoed back the
+/// exact payload that was sent in the preceding
+/// [`PingPacket`](protocol::PingPacket).
+fn check_pong_payload(sent: i64, echoed: i64) -> Result<()> {
+    if sent != echoed {
+        return Err(ServerError::LatencyMismatch.into());
+    }
+
+    Ok(())
+}
+
 /// Wraps a built connection
 pub struct StatusConnection {
     stream: TcpStream,
-    protocol_version: usize,
+    protocol_version: i32,
     address: String,
     port: u16,
 }
@@ -246,4 +528,200 @@ impl StatusConnection {
     pub async fn status(&mut self) -> Result<StatusResponse> {
         Ok(serde_json::from_str(&self.status_raw().await?).map_err(|e| ServerError::InvalidJson(e))?)
     }
+
+    /// Like [`status`](Self::status), but also measures the round-trip
+    /// latency to the server using the Ping/Pong packets that follow
+    /// the status response in the ServerListPing protocol.
+    pub async fn status_with_latency(&mut self) -> Result<(StatusResponse, Duration)> {
+        let status = self.status().await?;
+
+        let payload = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis() as i64)
+            .unwrap_or_default();
+
+        let start = Instant::now();
+
+        self.stream
+            .write_packet(protocol::PingPacket::new(payload))
+            .await
+            .context("failed to write ping packet")?;
+
+        let pong: protocol::PongPacket = self
+            .stream
+            .read_packet()
+            .await
+            .context("failed to read pong packet")?;
+
+        let elapsed = start.elapsed();
+
+        check_pong_payload(payload, pong.payload)?;
+
+        Ok((status, elapsed))
+    }
+
+    /// Performs a legacy (pre-1.7) Server List Ping, for servers too old
+    /// to understand the modern handshake+JSON protocol used by
+    /// [`status`](Self::status). If `status` fails because the server's
+    /// reply can't be read as a modern status response, calling this
+    /// method on a new connection is a reasonable fallback.
+    pub async fn status_legacy(&mut self) -> Result<LegacyStatusResponse> {
+        protocol::write_legacy_ping(&mut self.stream, &self.address, self.port)
+            .await
+            .context("failed to write legacy ping packet")?;
+
+        let raw = protocol::read_legacy_ping_response(&mut self.stream)
+            .await
+            .context("failed to read legacy ping response")?;
+
+        Ok(LegacyStatusResponse {
+            protocol_version: raw
+                .protocol_version
+                .parse()
+                .map_err(|_| ServerError::ProtocolError)?,
+            version: raw.version,
+            motd: raw.motd,
+            players_online: raw
+                .players_online
+                .parse()
+                .map_err(|_| ServerError::ProtocolError)?,
+            max_players: raw
+                .max_players
+                .parse()
+                .map_err(|_| ServerError::ProtocolError)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_pong_payload_accepts_a_matching_echo() {
+        assert!(check_pong_payload(1234, 1234).is_ok());
+    }
+
+    #[test]
+    fn check_pong_payload_rejects_a_mismatched_echo() {
+        let err = check_pong_payload(1234, 5678).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ServerError>(),
+            Some(ServerError::LatencyMismatch)
+        ));
+    }
+
+    #[test]
+    fn deserializes_and_normalizes_the_old_fml_mod_list() {
+        let status: StatusResponse = serde_json::from_str(
+            r#"{
+                "version": {"name": "1.12.2", "protocol": 340},
+                "players": {"max": 20, "online": 0},
+                "description": "A Modded Server",
+                "modinfo": {
+                    "type": "FML",
+                    "modList": [
+                        {"modid": "examplemod", "version": "1.0.0"}
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(status.forge_data.is_none());
+
+        let mods = status.mod_list();
+        assert_eq!(mods.len(), 1);
+        assert_eq!(mods[0].id, "examplemod");
+        assert_eq!(mods[0].version, "1.0.0");
+    }
+
+    #[test]
+    fn deserializes_and_normalizes_fml2_forge_data() {
+        let status: StatusResponse = serde_json::from_str(
+            r#"{
+                "version": {"name": "1.16.5", "protocol": 754},
+                "players": {"max": 20, "online": 0},
+                "description": "A Modded Server",
+                "forgeData": {
+                    "fmlNetworkVersion": 3,
+                    "channels": [
+                        {"res": "examplemod:main", "version": "1.0", "required": true}
+                    ],
+                    "mods": [
+                        {"modId": "examplemod", "modmarker": "1.0.0"}
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let forge_data = status.forge_data.as_ref().unwrap();
+        assert_eq!(forge_data.fml_network_version, 3);
+        assert_eq!(forge_data.channels[0].res, "examplemod:main");
+        assert!(forge_data.channels[0].required);
+
+        let mods = status.mod_list();
+        assert_eq!(mods.len(), 1);
+        assert_eq!(mods[0].id, "examplemod");
+        assert_eq!(mods[0].version, "1.0.0");
+    }
+
+    #[test]
+    fn mod_list_is_empty_for_vanilla_servers() {
+        let status: StatusResponse = serde_json::from_str(
+            r#"{
+                "version": {"name": "1.16.5", "protocol": 754},
+                "players": {"max": 20, "online": 0},
+                "description": "A Vanilla Server"
+            }"#,
+        )
+        .unwrap();
+
+        assert!(status.mod_list().is_empty());
+    }
+
+    fn status_with_favicon(favicon: Option<&str>) -> StatusResponse {
+        let favicon_json = match favicon {
+            Some(value) => format!("\"{value}\""),
+            None => "null".to_string(),
+        };
+
+        serde_json::from_str(&format!(
+            r#"{{
+                "version": {{"name": "1.16.5", "protocol": 754}},
+                "players": {{"max": 20, "online": 0}},
+                "description": "A Server",
+                "favicon": {favicon_json}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn favicon_png_decodes_a_valid_data_url() {
+        let status = status_with_favicon(Some("data:image/png;base64,aGVsbG8="));
+        let png = status.favicon_png().unwrap().unwrap();
+        assert_eq!(png, b"hello");
+    }
+
+    #[test]
+    fn favicon_png_rejects_a_missing_prefix() {
+        let status = status_with_favicon(Some("aGVsbG8="));
+        let err = status.favicon_png().unwrap().unwrap_err();
+        assert!(matches!(err, FaviconError::MissingPrefix));
+    }
+
+    #[test]
+    fn favicon_png_rejects_invalid_base64() {
+        let status = status_with_favicon(Some("data:image/png;base64,not-valid-base64!!"));
+        let err = status.favicon_png().unwrap().unwrap_err();
+        assert!(matches!(err, FaviconError::InvalidBase64(_)));
+    }
+
+    #[test]
+    fn favicon_png_is_none_without_a_favicon() {
+        let status = status_with_favicon(None);
+        assert!(status.favicon_png().is_none());
+    }
 }